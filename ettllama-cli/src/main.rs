@@ -1,22 +1,250 @@
-use eyre::Result;
+use eyre::{eyre, Result};
 use futures::{SinkExt, StreamExt};
 use http::Uri;
 use inquire::Text;
 use owo_colors::OwoColorize;
-use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
 use std::{
     env,
-    io::{self, Write},
+    fs::File,
+    io::{self, BufReader, Write},
+    pin::Pin,
     sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
 };
-use tokio::net::TcpStream;
-use tokio_rustls::TlsConnector;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_tungstenite::WebSocketStream;
 use tracing_subscriber::EnvFilter;
 use trust_dns_resolver::{
     config::{ResolverConfig, ResolverOpts},
     TokioAsyncResolver,
 };
-use tungstenite::Message;
+use tungstenite::{protocol::Role, Message};
+
+const CLIENT_CERT_VAR: &str = "CLIENT_CERT";
+const CLIENT_KEY_VAR: &str = "CLIENT_KEY";
+
+/// A frame sent to the server.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    /// Begin generating from `prompt`.
+    Request(InferenceRequest),
+    /// Abort the in-flight generation.
+    #[allow(dead_code)]
+    Cancel,
+}
+
+/// A generation request; the REPL only sets the prompt and takes the model defaults.
+#[derive(Serialize)]
+struct InferenceRequest {
+    prompt: String,
+}
+
+/// A frame streamed back from the server.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame {
+    Token {
+        token: String,
+    },
+    Done {
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        finish_reason: FinishReason,
+    },
+}
+
+/// Why a generation stopped.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FinishReason {
+    EndOfText,
+    Stop,
+    Length,
+    Cancelled,
+}
+
+/// Best-effort randomness for a WebSocket masking key / `Sec-WebSocket-Key`; neither needs
+/// to be cryptographically secure; just unpredictable enough for a caching proxy, so this
+/// leans on the OS-seeded per-process hasher instead of pulling in a `rand` dependency.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    use std::hash::{BuildHasher, Hasher};
+    let mut out = [0u8; N];
+    let mut i = 0;
+    while i < N {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_usize(i);
+        let word = hasher.finish().to_le_bytes();
+        let take = word.len().min(N - i);
+        out[i..i + take].copy_from_slice(&word[..take]);
+        i += take;
+    }
+    out
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        out.push(ALPHABET[(b[0] >> 2) as usize] as char);
+        out.push(ALPHABET[(((b[0] & 0x03) << 4) | (b[1] >> 4)) as usize] as char);
+        out.push(if chunk.len() > 1 {
+            ALPHABET[(((b[1] & 0x0f) << 2) | (b[2] >> 6)) as usize] as char
+        } else {
+            '='
+        });
+        out.push(if chunk.len() > 2 {
+            ALPHABET[(b[2] & 0x3f) as usize] as char
+        } else {
+            '='
+        });
+    }
+    out
+}
+
+fn websocket_key() -> String {
+    base64_encode(&random_bytes::<16>())
+}
+
+/// Encodes `payload` as a single masked client-to-server WebSocket text frame (RFC 6455
+/// section 5.2). `tokio_tungstenite`'s handshake always does a write-then-read round trip
+/// before a single frame can be sent, which rules out pipelining the first prompt into the
+/// same early-data flight as the upgrade request, so this frame is built by hand instead.
+fn mask_text_frame(payload: &[u8]) -> Vec<u8> {
+    let mask = random_bytes::<4>();
+    let len = payload.len();
+
+    let mut frame = vec![0x81]; // FIN + text opcode
+    if len <= 125 {
+        frame.push(0x80 | len as u8);
+    } else if len <= u16::MAX as usize {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(0x80 | 127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+/// Builds the raw HTTP/1.1 Upgrade request tungstenite would otherwise send for us.
+fn upgrade_request(uri: &Uri, key: &str) -> Vec<u8> {
+    let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let host = uri.host().unwrap_or_default();
+    format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {key}\r\n\r\n"
+    )
+    .into_bytes()
+}
+
+/// Reads the server's response to [`upgrade_request`], returning any bytes read past the
+/// header terminator (normally none, but a pipelined server could send more) so they can
+/// be replayed into the WebSocket stream instead of silently dropped.
+async fn read_upgrade_response(stream: &mut TlsStream<TcpStream>) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(eyre!("server closed the connection during the WebSocket upgrade"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let Some(header_end) = buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4) else {
+            continue;
+        };
+        let status_line = std::str::from_utf8(&buf[..header_end])
+            .unwrap_or_default()
+            .lines()
+            .next()
+            .unwrap_or_default();
+        if !status_line.contains(" 101 ") {
+            return Err(eyre!("server refused the WebSocket upgrade: {status_line}"));
+        }
+        return Ok(buf[header_end..].to_vec());
+    }
+}
+
+/// Replays bytes read past the hand-rolled upgrade response before forwarding to `inner`,
+/// so [`WebSocketStream::from_raw_socket`] sees a contiguous stream as if it had done the
+/// handshake itself.
+struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S: Unpin> Unpin for PrefixedStream<S> {}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let take = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..take]);
+            self.prefix.drain(..take);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Prints tokens as they stream in and the final `! <finish reason>` line, shared by the
+/// early-data first prompt and every later REPL turn.
+async fn stream_response(
+    reader: &mut (impl futures::Stream<Item = tungstenite::Result<Message>> + Unpin),
+) -> Result<()> {
+    while let Some(Ok(Message::Text(frame))) = reader.next().await {
+        match serde_json::from_str::<ServerFrame>(&frame)? {
+            ServerFrame::Token { token } => {
+                print!("{}", token.green());
+                io::stdout().lock().flush()?;
+            }
+            ServerFrame::Done {
+                prompt_tokens,
+                completion_tokens,
+                finish_reason,
+            } => {
+                println!();
+                println!(
+                    "{} {} ({prompt_tokens} prompt + {completion_tokens} completion tokens)",
+                    "!".green(),
+                    format!("{finish_reason:?}").dimmed(),
+                );
+                break;
+            }
+        }
+    }
+    io::stdout().lock().flush()?;
+    Ok(())
+}
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
@@ -35,12 +263,38 @@ async fn main() -> Result<()> {
         )
     }));
 
-    let connector = TlsConnector::from(Arc::new(
-        ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(cert_store)
-            .with_no_client_auth(),
-    ));
+    let cert_file = File::open(env::var(CLIENT_CERT_VAR)?)?;
+    let mut cert_reader = BufReader::new(cert_file);
+    let certs = rustls_pemfile::certs(&mut cert_reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    let key_file = File::open(env::var(CLIENT_KEY_VAR)?)?;
+    let mut key_reader = BufReader::new(key_file);
+    let key = rustls_pemfile::pkcs8_private_keys(&mut key_reader)?
+        .into_iter()
+        .map(PrivateKey)
+        .next()
+        .expect("PEM-encoded private key");
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(cert_store)
+        .with_client_auth_cert(certs, key)?;
+    // 0-RTT: on a resumed session the ClientHello can carry application data. We hand-roll
+    // the WebSocket upgrade below so the upgrade request *and* the REPL's first prompt are
+    // both written before we ever read from the socket, letting them ride the same
+    // early-data flight on a resumed session. `in_memory_sessions` is the only
+    // `ClientSessionStore` this rustls version supports out of the box; it doesn't survive
+    // past this process, but that's fine — we open a throwaway priming connection below to
+    // populate it before the real one, so the real connection always has a session to
+    // resume. Early data can be replayed by a man-in-the-middle, so the server only
+    // accepts a bounded amount of it and only the first prompt of a connection is ever
+    // sent this way.
+    config.enable_early_data = true;
+    config.resumption = rustls::client::Resumption::in_memory_sessions(8);
+
+    let connector = TlsConnector::from(Arc::new(config)).early_data(true);
 
     let uri = env::args().nth(1).expect("server URI").parse::<Uri>()?;
     let port = uri.port_u16().unwrap_or(3000);
@@ -51,16 +305,62 @@ async fn main() -> Result<()> {
         .into_iter()
         .next()
         .expect("server IP address");
+    let server_name = ServerName::try_from(uri.host().unwrap())?;
 
-    let stream = connector
-        .connect(
-            ServerName::try_from(uri.host().unwrap())?,
-            TcpStream::connect((server_ip, port)).await?,
-        )
+    let Ok(first_prompt) = Text::new("user:")
+        .with_placeholder("your prompt here...")
+        .prompt()
+    else {
+        return Ok(());
+    };
+
+    // Throwaway connection so the real one below has a session ticket to resume: a cache
+    // that's only ever asked to store what it's never been given back can't resume
+    // anything. A short read pumps the server's post-handshake NewSessionTicket message(s)
+    // through rustls before we drop the connection.
+    {
+        let mut priming_stream = connector
+            .connect(
+                server_name.clone(),
+                TcpStream::connect((server_ip, port)).await?,
+            )
+            .await?;
+        let mut discard = [0u8; 256];
+        let _ = tokio::time::timeout(Duration::from_millis(200), priming_stream.read(&mut discard))
+            .await;
+    }
+
+    let mut stream = connector
+        .connect(server_name, TcpStream::connect((server_ip, port)).await?)
+        .await?;
+
+    let ws_key = websocket_key();
+    let first_request = ClientFrame::Request(InferenceRequest {
+        prompt: first_prompt,
+    });
+    stream.write_all(&upgrade_request(&uri, &ws_key)).await?;
+    stream
+        .write_all(&mask_text_frame(
+            serde_json::to_string(&first_request)?.as_bytes(),
+        ))
         .await?;
-    let (ws_stream, _) = tokio_tungstenite::client_async(uri, stream).await?;
+    stream.flush().await?;
+
+    let leftover = read_upgrade_response(&mut stream).await?;
+    let ws_stream = WebSocketStream::from_raw_socket(
+        PrefixedStream {
+            prefix: leftover,
+            inner: stream,
+        },
+        Role::Client,
+        None,
+    )
+    .await;
     let (mut writer, mut reader) = ws_stream.split();
 
+    print!("{} bot: ", "!".green());
+    stream_response(&mut reader).await?;
+
     loop {
         let Ok(prompt) = Text::new("user:")
             .with_placeholder("your prompt here...")
@@ -69,21 +369,15 @@ async fn main() -> Result<()> {
             break;
         };
 
-        writer.send(Message::Text(prompt)).await?;
+        let request = ClientFrame::Request(InferenceRequest { prompt });
+        writer
+            .send(Message::Text(serde_json::to_string(&request)?))
+            .await?;
         writer.flush().await?;
 
         print!("{} bot: ", "!".green());
-
-        while let Some(Ok(Message::Text(tok))) = reader.next().await {
-            if tok.is_empty() {
-                break;
-            }
-
-            print!("{}", tok.green());
-            io::stdout().lock().flush()?;
-        }
-
-        println!();
-        io::stdout().lock().flush()?;
+        stream_response(&mut reader).await?;
     }
+
+    Ok(())
 }
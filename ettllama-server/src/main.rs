@@ -1,19 +1,31 @@
-use eyre::Result;
+use clap::Parser;
+use eyre::{eyre, Result};
 use futures::{SinkExt, StreamExt};
 use maplit::hashmap;
-use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
-    convert::Infallible, env, fs::File, io::BufReader, net::SocketAddr, path::PathBuf, sync::Arc,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    env,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 use string_template::Template;
 use tokio::{
-    fs,
     net::{TcpListener, TcpStream},
-    task::yield_now,
+    sync::mpsc,
 };
 use tokio_rustls::{server::TlsStream, TlsAcceptor};
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 use tungstenite::Message;
 
@@ -26,24 +38,295 @@ const TEMPLATE_FILE_VAR: &str = "TEMPLATE_FILE";
 const ACCEL_OFFLOAD_LAYERS_VAR: &str = "ACCEL_OFFLOAD_LAYERS";
 const INFERENCE_BATCH_SIZE_VAR: &str = "INFERENCE_BATCH_SIZE";
 const INFERENCE_THREADS_VAR: &str = "INFERENCE_THREADS";
+const CLIENT_CA_VAR: &str = "CLIENT_CA";
+const CLIENT_FINGERPRINTS_VAR: &str = "CLIENT_FINGERPRINTS";
 
-// running multi-threaded breaks ggml-sys with multiple client?
-#[tokio::main(flavor = "current_thread")]
+/// Caps replayable 0-RTT data to a WebSocket upgrade request plus one short prompt frame.
+/// Early data is replayable by a man-in-the-middle, so this is sized to hold only what the
+/// CLI ever actually sends this way (see `ettllama-cli`), not `u32::MAX`.
+const MAX_EARLY_DATA_BYTES: u32 = 16 * 1024;
+
+/// Assigns each connection a stable inference session id for the worker.
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A unit of work handed to the inference worker.
+enum Job {
+    /// Feed `prompt` into the session and stream the completion back over `tokens`.
+    Prompt {
+        session_id: u64,
+        prompt: String,
+        params: llm::InferenceParameters,
+        tokens: mpsc::UnboundedSender<InferenceEvent>,
+    },
+    /// Stop this session's active generation without dropping its `InferenceSession`, so a
+    /// later `Prompt` for the same session continues from the same KV state. Sent whenever
+    /// the handler ends a generation itself (stop sequence, `max_tokens`, cancellation)
+    /// instead of waiting for the worker to notice the dropped `tokens` receiver, which
+    /// would cost one more silently-discarded token of KV state first.
+    Stop { session_id: u64 },
+    /// Drop a session once its connection goes away.
+    Close { session_id: u64 },
+}
+
+/// A single streamed event from the worker back to a connection handler.
+enum InferenceEvent {
+    /// Number of tokens the prompt occupied, emitted once before generation.
+    Prompt(usize),
+    Token(Vec<u8>),
+    /// Generation ended on the worker's side: the model emitted end-of-text, or feeding the
+    /// prompt or inferring the next token failed.
+    Done,
+}
+
+/// A frame sent by a client over the WebSocket.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    /// Begin generating from `prompt` with optional sampling overrides.
+    Request(InferenceRequest),
+    /// Abort the in-flight generation for this connection.
+    Cancel,
+}
+
+/// Per-request sampling configuration; every field falls back to the model default.
+#[derive(Deserialize)]
+struct InferenceRequest {
+    prompt: String,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    stop: Vec<String>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    top_k: Option<usize>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    repeat_penalty: Option<f32>,
+}
+
+/// A frame streamed back to a client.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame {
+    Token {
+        token: String,
+    },
+    Done {
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        finish_reason: FinishReason,
+    },
+}
+
+/// Why a generation stopped.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FinishReason {
+    /// The model emitted its end-of-text token. Also reported when the worker ends the
+    /// generation because feeding the prompt or inferring a token failed.
+    EndOfText,
+    /// A client-supplied stop sequence was produced.
+    Stop,
+    /// `max_tokens` was reached.
+    Length,
+    /// The client sent a `cancel` frame or hung up.
+    Cancelled,
+}
+
+/// Command-line flags. Every setting is optional here; unset flags fall through to the
+/// config file, then to the matching environment variable, then to a default where one
+/// exists; a required setting with no value anywhere is an error.
+#[derive(Parser)]
+#[command(about = "ettllama inference server")]
+struct Cli {
+    /// TOML or JSON config file providing defaults for any unset flag.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Server certificate chain (PEM).
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// Server private key (PKCS#8 PEM).
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// Trusted client CA bundle for mTLS (PEM).
+    #[arg(long)]
+    client_ca: Option<PathBuf>,
+    /// Comma-separated allow-list of client certificate SHA-256 fingerprints.
+    #[arg(long)]
+    client_fingerprints: Option<String>,
+    /// Address to bind.
+    #[arg(long)]
+    addr: Option<SocketAddr>,
+    /// Path to the model weights.
+    #[arg(long)]
+    model_path: Option<PathBuf>,
+    /// Model architecture; auto-detected when omitted.
+    #[arg(long)]
+    model_arch: Option<String>,
+    /// Prompt template file.
+    #[arg(long)]
+    template_file: Option<PathBuf>,
+    /// Layers to offload when built with GPU acceleration.
+    #[arg(long)]
+    accel_offload_layers: Option<usize>,
+    /// Prompt batch size.
+    #[arg(long)]
+    inference_batch_size: Option<usize>,
+    /// Inference thread count.
+    #[arg(long)]
+    inference_threads: Option<usize>,
+}
+
+/// The same fields as [`Cli`], as read from a config file.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct FileConfig {
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    client_fingerprints: Option<String>,
+    addr: Option<SocketAddr>,
+    model_path: Option<PathBuf>,
+    model_arch: Option<String>,
+    template_file: Option<PathBuf>,
+    accel_offload_layers: Option<usize>,
+    inference_batch_size: Option<usize>,
+    inference_threads: Option<usize>,
+}
+
+/// Fully-resolved, validated server configuration threaded into every connection.
+struct Config {
+    tls_cert: PathBuf,
+    tls_key: PathBuf,
+    client_ca: PathBuf,
+    client_fingerprints: Option<HashSet<String>>,
+    addr: SocketAddr,
+    model_path: PathBuf,
+    model_arch: Option<String>,
+    accel_offload_layers: Option<usize>,
+    session_config: llm::InferenceSessionConfig,
+    template: String,
+}
+
+/// Resolves one setting with CLI overriding file overriding environment.
+fn layer<T>(cli: Option<T>, file: Option<T>, env_var: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(value) = cli.or(file) {
+        return Ok(Some(value));
+    }
+    match env::var(env_var) {
+        Ok(raw) => Ok(Some(raw.parse()?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Unwraps a required setting, reporting which knob is missing.
+fn require<T>(value: Option<T>, name: &str) -> Result<T> {
+    value.ok_or_else(|| eyre!("missing required `{name}` (set via --{name}, config file, or env)"))
+}
+
+impl Config {
+    /// Resolves the layered configuration, failing fast on anything missing or invalid.
+    fn resolve(cli: Cli) -> Result<Self> {
+        let file = match &cli.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)?;
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    serde_json::from_str(&text)?
+                } else {
+                    toml::from_str(&text)?
+                }
+            }
+            None => FileConfig::default(),
+        };
+
+        let mut session_config = llm::InferenceSessionConfig::default();
+        if let Some(n) = layer(
+            cli.inference_batch_size,
+            file.inference_batch_size,
+            INFERENCE_BATCH_SIZE_VAR,
+        )? {
+            session_config.n_batch = n;
+        }
+        if let Some(n) = layer(
+            cli.inference_threads,
+            file.inference_threads,
+            INFERENCE_THREADS_VAR,
+        )? {
+            session_config.n_threads = n;
+        }
+
+        let template_file = require(
+            layer(cli.template_file, file.template_file, TEMPLATE_FILE_VAR)?,
+            "template-file",
+        )?;
+
+        let model_arch = layer(cli.model_arch, file.model_arch, MODEL_ARCH_VAR)?;
+        if let Some(arch) = &model_arch {
+            arch.parse::<llm::ModelArchitecture>()
+                .map_err(|_| eyre!("unsupported model architecture `{arch}`"))?;
+        }
+
+        Ok(Self {
+            tls_cert: require(layer(cli.tls_cert, file.tls_cert, TLS_CERT_VAR)?, "tls-cert")?,
+            tls_key: require(layer(cli.tls_key, file.tls_key, TLS_KEY_VAR)?, "tls-key")?,
+            client_ca: require(
+                layer(cli.client_ca, file.client_ca, CLIENT_CA_VAR)?,
+                "client-ca",
+            )?,
+            client_fingerprints: layer(
+                cli.client_fingerprints,
+                file.client_fingerprints,
+                CLIENT_FINGERPRINTS_VAR,
+            )?
+            .map(|list| {
+                list.split(',')
+                    .map(|fp| fp.trim().to_ascii_lowercase())
+                    .collect()
+            }),
+            addr: layer(cli.addr, file.addr, ADDR_VAR)?
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 3000))),
+            model_path: require(
+                layer(cli.model_path, file.model_path, MODEL_PATH_VAR)?,
+                "model-path",
+            )?,
+            model_arch,
+            accel_offload_layers: layer(
+                cli.accel_offload_layers,
+                file.accel_offload_layers,
+                ACCEL_OFFLOAD_LAYERS_VAR,
+            )?,
+            session_config,
+            template: std::fs::read_to_string(template_file)?,
+        })
+    }
+}
+
+// ggml-sys is not reentrant, so all model/session calls are serialized onto one
+// dedicated thread; the async runtime is free to be multi-threaded for I/O.
+#[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    dotenv::dotenv()?;
+    dotenv::dotenv().ok();
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .compact()
         .init();
 
-    let cert_file = File::open(env::var(TLS_CERT_VAR)?)?;
+    let config = Arc::new(Config::resolve(Cli::parse())?);
+
+    let cert_file = File::open(&config.tls_cert)?;
     let mut cert_reader = BufReader::new(cert_file);
     let certs = rustls_pemfile::certs(&mut cert_reader)?
         .into_iter()
         .map(Certificate)
         .collect::<Vec<_>>();
-    let key_file = File::open(env::var(TLS_KEY_VAR)?)?;
+    let key_file = File::open(&config.tls_key)?;
     let mut key_reader = BufReader::new(key_file);
     let key = rustls_pemfile::pkcs8_private_keys(&mut key_reader)?
         .into_iter()
@@ -51,14 +334,14 @@ async fn main() -> Result<()> {
         .next()
         .expect("PEM-encoded private key");
 
-    let model: Arc<dyn llm::Model> = Arc::from(llm::load_dynamic(
-        env::var(MODEL_ARCH_VAR)?.parse().ok(),
-        &env::var(MODEL_PATH_VAR)?.parse::<PathBuf>()?,
+    let model: Box<dyn llm::Model> = llm::load_dynamic(
+        config.model_arch.as_ref().and_then(|arch| arch.parse().ok()),
+        &config.model_path,
         llm::TokenizerSource::Embedded,
         llm::ModelParameters {
             use_gpu: accelerated!(),
             gpu_layers: if accelerated!() {
-                env::var(ACCEL_OFFLOAD_LAYERS_VAR)?.parse().ok()
+                config.accel_offload_layers
             } else {
                 None
             },
@@ -79,23 +362,70 @@ async fn main() -> Result<()> {
                 tensor_count,
             } => info!("loaded model ({file_size}B, {tensor_count} tensors)"),
         },
-    )?);
+    )?;
+
+    // The worker owns the model and every session exclusively; handlers talk to it
+    // over this channel so ggml calls never race.
+    let session_config = config.session_config;
+    let (jobs_tx, jobs_rx) = mpsc::unbounded_channel::<Job>();
+    std::thread::Builder::new()
+        .name("inference-worker".into())
+        .spawn(move || inference_worker(model, session_config, jobs_rx))?;
+
+    let ca_file = File::open(&config.client_ca)?;
+    let mut ca_reader = BufReader::new(ca_file);
+    let mut client_roots = RootCertStore::empty();
+    for ca in rustls_pemfile::certs(&mut ca_reader)? {
+        client_roots.add(&Certificate(ca))?;
+    }
 
-    let acceptor = TlsAcceptor::from(Arc::new(
-        ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)?,
-    ));
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        // `new` returns the verifier itself; `.boxed()` wraps it in the
+        // `Arc<dyn ClientCertVerifier>` that `with_client_cert_verifier` takes.
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_roots).boxed())
+        .with_single_cert(certs, key)?;
+    // Accept 0-RTT early data from resumed clients so the first prompt can ride the
+    // ClientHello. Early data may be replayed, so this is bounded and clients must only
+    // send the first prompt of a session this way (see the CLI).
+    server_config.max_early_data_size = MAX_EARLY_DATA_BYTES;
 
-    let socket = TcpListener::bind(env::var(ADDR_VAR)?.parse::<SocketAddr>()?).await?;
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let socket = TcpListener::bind(config.addr).await?;
 
     while let Ok((stream, addr)) = socket.accept().await {
         let Ok(stream) = acceptor.accept(stream).await else {
             continue;
         };
-        info!("{addr} connected with TLS!");
-        tokio::spawn(handler(stream, addr, Arc::clone(&model)));
+
+        // mTLS guarantees an authenticated client certificate; key the audit trail to it.
+        let Some(fingerprint) = stream.get_ref().1.peer_certificates().and_then(|certs| {
+            certs.first().map(|leaf| {
+                let mut hasher = Sha256::new();
+                hasher.update(&leaf.0);
+                format!("{:x}", hasher.finalize())
+            })
+        }) else {
+            info!("{addr} presented no client certificate, rejecting");
+            continue;
+        };
+
+        if let Some(allowed) = &config.client_fingerprints {
+            if !allowed.contains(&fingerprint) {
+                info!("{addr} client {fingerprint} not in allow-list, rejecting");
+                continue;
+            }
+        }
+
+        info!("{addr} connected with TLS! (client {fingerprint})");
+        tokio::spawn(handler(
+            stream,
+            addr,
+            jobs_tx.clone(),
+            fingerprint,
+            Arc::clone(&config),
+        ));
     }
 
     Ok(())
@@ -112,61 +442,273 @@ macro_rules! accelerated {
     };
 }
 
+/// Tells the worker to drop a session's state when the connection handler exits, by any
+/// path: normal return, a `?`-propagated error, or a panic. Without this, any failure
+/// before the handler's normal return (a write error, a malformed UTF-8 token, ...) would
+/// leak that session's `InferenceSession` in the worker forever.
+struct SessionGuard {
+    session_id: u64,
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let _ = self.jobs.send(Job::Close {
+            session_id: self.session_id,
+        });
+    }
+}
+
 async fn handler(
     stream: TlsStream<TcpStream>,
     addr: SocketAddr,
-    model: Arc<dyn llm::Model>,
+    jobs: mpsc::UnboundedSender<Job>,
+    fingerprint: String,
+    config: Arc<Config>,
 ) -> Result<()> {
     let stream = tokio_tungstenite::accept_async(stream).await?;
     info!("{addr} completed WebSocket handshake!");
     let (mut writer, mut reader) = stream.split();
 
-    let mut session = model.start_session(llm::InferenceSessionConfig {
-        n_batch: env::var(INFERENCE_BATCH_SIZE_VAR)?.parse()?,
-        n_threads: env::var(INFERENCE_THREADS_VAR)?.parse()?,
-        ..Default::default()
-    });
+    let session_id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+    let _session_guard = SessionGuard {
+        session_id,
+        jobs: jobs.clone(),
+    };
+    let template = Template::new(&config.template);
 
-    let template = Template::new(&fs::read_to_string(env::var(TEMPLATE_FILE_VAR)?).await?);
+    while let Some(Ok(Message::Text(text))) = reader.next().await {
+        let request = match serde_json::from_str::<ClientFrame>(&text) {
+            // A stray `cancel` with nothing running is a no-op.
+            Ok(ClientFrame::Request(request)) => request,
+            Ok(ClientFrame::Cancel) => continue,
+            Err(err) => {
+                warn!("{addr} sent an unparseable frame: {err}");
+                continue;
+            }
+        };
 
-    while let Some(Ok(Message::Text(prompt))) = reader.next().await {
         let mut hasher = Sha256::new();
-        hasher.update(&prompt);
-        info!("{addr} submitted prompt with hash {:x}", hasher.finalize());
+        hasher.update(&request.prompt);
+        info!(
+            "{addr} (client {fingerprint}) submitted prompt with hash {:x}",
+            hasher.finalize()
+        );
 
         let template_params = hashmap! {
-            "prompt" => prompt.as_str(),
+            "prompt" => request.prompt.as_str(),
         };
         let prompt = template.render(&template_params);
+        let params = inference_params(&request);
+        // An empty stop string matches `str::contains` immediately, stopping generation
+        // after the very first token; that's never a meaningful request, so drop it.
+        let stop: Vec<String> = request.stop.into_iter().filter(|s| !s.is_empty()).collect();
+        let max_tokens = request.max_tokens;
 
-        for word in prompt.split_whitespace() {
-            session.feed_prompt(&*model, word, &mut llm::OutputRequest::default(), |_| {
-                Ok::<_, Infallible>(llm::InferenceFeedback::Continue)
-            })?;
-            yield_now().await;
-        }
+        // Hand the prompt to the worker and stream tokens back as they land; while we
+        // await, the worker interleaves other sessions' generation.
+        let (tokens_tx, mut tokens_rx) = mpsc::unbounded_channel();
+        jobs.send(Job::Prompt {
+            session_id,
+            prompt,
+            params,
+            tokens: tokens_tx,
+        })?;
 
-        loop {
-            let Ok(tok) = session.infer_next_token(
-                &*model,
-                &llm::InferenceParameters::default(),
-                &mut llm::OutputRequest::default(),
-                &mut rand::thread_rng(),
-            ) else {
-                break;
-            };
+        let mut prompt_tokens = 0;
+        let mut completion_tokens = 0;
+        let mut completion = String::new();
+        let finish_reason = loop {
+            tokio::select! {
+                event = tokens_rx.recv() => match event {
+                    Some(InferenceEvent::Prompt(count)) => prompt_tokens = count,
+                    Some(InferenceEvent::Token(tok)) => {
+                        let tok = String::from_utf8(tok)?;
+                        completion_tokens += 1;
+                        completion.push_str(&tok);
+                        writer
+                            .send(Message::Text(serde_json::to_string(&ServerFrame::Token {
+                                token: tok,
+                            })?))
+                            .await?;
+                        writer.flush().await?;
+
+                        // `tok` can decode to more than one character, so a stop sequence
+                        // can land in the middle of it rather than exactly at the new
+                        // tail; check the whole running completion, not just its end.
+                        if stop.iter().any(|s| completion.contains(s.as_str())) {
+                            break FinishReason::Stop;
+                        }
+                        if max_tokens.is_some_and(|max| completion_tokens >= max) {
+                            break FinishReason::Length;
+                        }
+                    }
+                    Some(InferenceEvent::Done) | None => break FinishReason::EndOfText,
+                },
+                // Out-of-band cancellation: a `cancel` frame (or a dropped connection)
+                // aborts generation mid-stream. The `Job::Stop` sent after this loop then
+                // tells the worker to stop.
+                frame = reader.next() => match frame {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                        Ok(ClientFrame::Cancel) => break FinishReason::Cancelled,
+                        // The protocol is strict request/response: one `request` in
+                        // flight per connection at a time. A pipelining client sending
+                        // another one before `done` has no well-defined outcome here, so
+                        // refuse it loudly instead of silently dropping the prompt.
+                        Ok(ClientFrame::Request(_)) => {
+                            return Err(eyre!(
+                                "{addr} sent a request while a generation was still \
+                                 streaming; one request at a time per connection"
+                            ));
+                        }
+                        Err(_) => {}
+                    },
+                    Some(Ok(_)) => {}
+                    _ => break FinishReason::Cancelled,
+                },
+            }
+        };
 
-            assert!(!tok.is_empty()); // otherwise it breaks everything
-            writer.send(Message::Text(String::from_utf8(tok)?)).await?;
-            writer.flush().await?;
-            yield_now().await;
+        // The worker only learns a generation ended once it fails to send into this
+        // connection's (by-now-dropped) `tokens_rx`, which costs it one more
+        // `infer_next_token` call first — a token of KV state the client never sees. Tell
+        // it to stop right away instead of relying on that. `EndOfText` needs no message:
+        // the worker already reaped itself before ever sending that event.
+        if !matches!(finish_reason, FinishReason::EndOfText) {
+            let _ = jobs.send(Job::Stop { session_id });
         }
 
-        writer.send(Message::Text(String::new())).await?;
+        writer
+            .send(Message::Text(serde_json::to_string(&ServerFrame::Done {
+                prompt_tokens,
+                completion_tokens,
+                finish_reason,
+            })?))
+            .await?;
         writer.flush().await?;
-        yield_now().await;
     }
 
     info!("{addr} disconnected!");
     Ok(())
 }
+
+/// Builds [`llm::InferenceParameters`] from a request, applying any sampling overrides
+/// on top of the default top-p/top-k sampler.
+fn inference_params(request: &InferenceRequest) -> llm::InferenceParameters {
+    let mut sampler = llm::samplers::TopPTopK::default();
+    if let Some(temperature) = request.temperature {
+        sampler.temperature = temperature;
+    }
+    if let Some(top_k) = request.top_k {
+        sampler.top_k = top_k;
+    }
+    if let Some(top_p) = request.top_p {
+        sampler.top_p = top_p;
+    }
+    if let Some(repeat_penalty) = request.repeat_penalty {
+        sampler.repeat_penalty = repeat_penalty;
+    }
+    llm::InferenceParameters {
+        sampler: Arc::new(sampler),
+    }
+}
+
+/// Runs on a dedicated OS thread and exclusively owns the model and every
+/// [`llm::InferenceSession`]. Prompts are fed up front, then active generations are
+/// advanced one token per round so concurrent clients interleave fairly without ever
+/// issuing reentrant ggml calls.
+fn inference_worker(
+    model: Box<dyn llm::Model>,
+    session_config: llm::InferenceSessionConfig,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+) {
+    struct Active {
+        params: llm::InferenceParameters,
+        tokens: mpsc::UnboundedSender<InferenceEvent>,
+    }
+
+    // Keyed by session_id, not a flat list: a handler can drop `tokens_rx` and enqueue the
+    // next `Job::Prompt` for the same session before this worker has reaped the finished
+    // generation, and a `Vec` would briefly hold both, double-advancing the session's KV
+    // state with a token nobody reads. Inserting under the session's key instead just
+    // replaces the stale entry.
+    fn start(
+        model: &dyn llm::Model,
+        session_config: llm::InferenceSessionConfig,
+        sessions: &mut HashMap<u64, llm::InferenceSession>,
+        active: &mut HashMap<u64, Active>,
+        job: Job,
+    ) {
+        match job {
+            Job::Prompt {
+                session_id,
+                prompt,
+                params,
+                tokens,
+            } => {
+                let session = sessions
+                    .entry(session_id)
+                    .or_insert_with(|| model.start_session(session_config));
+
+                let prompt_tokens = model.tokenize(&prompt, false).map_or(0, |toks| toks.len());
+                let _ = tokens.send(InferenceEvent::Prompt(prompt_tokens));
+
+                for word in prompt.split_whitespace() {
+                    if session
+                        .feed_prompt(model, word, &mut llm::OutputRequest::default(), |_| {
+                            Ok::<_, Infallible>(llm::InferenceFeedback::Continue)
+                        })
+                        .is_err()
+                    {
+                        let _ = tokens.send(InferenceEvent::Done);
+                        return;
+                    }
+                }
+
+                active.insert(session_id, Active { params, tokens });
+            }
+            Job::Stop { session_id } => {
+                active.remove(&session_id);
+            }
+            Job::Close { session_id } => {
+                sessions.remove(&session_id);
+                active.remove(&session_id);
+            }
+        }
+    }
+
+    let mut sessions: HashMap<u64, llm::InferenceSession> = HashMap::new();
+    let mut active: HashMap<u64, Active> = HashMap::new();
+
+    loop {
+        // Block for work only when idle, otherwise keep the round-robin spinning.
+        if active.is_empty() {
+            match jobs.blocking_recv() {
+                Some(job) => start(&*model, session_config, &mut sessions, &mut active, job),
+                None => break,
+            }
+        }
+        while let Ok(job) = jobs.try_recv() {
+            start(&*model, session_config, &mut sessions, &mut active, job);
+        }
+
+        // One token per active session per pass.
+        active.retain(|session_id, gen| {
+            let Some(session) = sessions.get_mut(session_id) else {
+                return false;
+            };
+            match session.infer_next_token(
+                &*model,
+                &gen.params,
+                &mut llm::OutputRequest::default(),
+                &mut rand::thread_rng(),
+            ) {
+                Ok(tok) => gen.tokens.send(InferenceEvent::Token(tok)).is_ok(),
+                Err(_) => {
+                    let _ = gen.tokens.send(InferenceEvent::Done);
+                    false
+                }
+            }
+        });
+    }
+}